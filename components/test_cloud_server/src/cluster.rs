@@ -1,18 +1,31 @@
 // Copyright 2022 TiKV Project Authors. Licensed under Apache-2.0.
 
-use std::{collections::HashMap, ops::Range, path::Path, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    ops::Range,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use cloud_server::TiKVServer;
+use concurrency_manager::{ConcurrencyManager, KeyHandleGuard};
 use futures::executor::block_on;
 use grpcio::{Channel, ChannelBuilder, EnvBuilder, Environment};
-use kvengine::dfs::InMemFS;
+use kvengine::dfs::{Dfs, InMemFS, S3Fs};
 use kvproto::{
-    kvrpcpb::{CommitRequest, Context, Mutation, Op, PrewriteRequest, SplitRegionRequest},
+    eraftpb::MessageType,
+    kvrpcpb::{
+        BatchGetRequest, CommitRequest, Context, GetRequest, KvPair, Mutation, Op,
+        PessimisticLockRequest, PessimisticRollbackRequest, PrewriteRequest, PrewriteResponse,
+        SplitRegionRequest,
+    },
     raft_cmdpb::RaftCmdRequest,
+    raft_serverpb::RaftMessage,
     tikvpb::TikvClient,
 };
 use pd_client::PdClient;
-use rfstore::{store::Callback, RaftStoreRouter};
+use rfstore::{store::Callback, RaftRouter, RaftStoreRouter};
 use security::SecurityManager;
 use tempfile::TempDir;
 use test_raftstore::{find_peer, TestPdClient};
@@ -22,6 +35,7 @@ use tikv_util::{
     thread_group::GroupProperties,
     time::Instant,
 };
+use txn_types::{Key, Lock, LockType};
 
 // Retry if encounter error
 macro_rules! retry_req {
@@ -42,6 +56,52 @@ macro_rules! retry_req {
     };
 }
 
+/// Selects the `Dfs` backend a [`ServerCluster`] runs its nodes against.
+pub enum DfsKind {
+    /// The default in-memory FS, fast but unable to catch bugs that only
+    /// surface against a real object store (upload retries, eventual
+    /// consistency, restart-from-remote-storage).
+    InMem,
+    /// An S3-compatible backend, e.g. a local MinIO/Garage instance.
+    S3 {
+        endpoint: String,
+        bucket: String,
+        key_id: String,
+        secret_key: String,
+    },
+}
+
+/// The outcome a [`MessageFilter`] wants for a given Raft message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterAction {
+    Pass,
+    Drop,
+    Delay(Duration),
+}
+
+pub type MessageFilter = Arc<dyn Fn(&RaftMessage) -> FilterAction + Send + Sync>;
+
+#[derive(Default)]
+struct NodeFilters {
+    send: Vec<MessageFilter>,
+    recv: Vec<MessageFilter>,
+}
+
+/// Per-node send/recv filter chains.
+///
+/// NOTE: these only apply to messages a test explicitly replays through
+/// [`ServerCluster::send_raft_message`]. `cloud_server`'s own inter-node
+/// transport (the `RaftClient`/gRPC `Raft`/`BatchRaft` streams that carry the
+/// cluster's organic heartbeats, appends and votes) is internal to
+/// `TiKVServer::setup` and isn't reachable from this crate, so installing the
+/// filter chain on the real send path — which is what partition-healing,
+/// split-brain and stale-leader-lease tests need — would require a hook
+/// added in `cloud_server` itself. That crate isn't part of this tree, so
+/// this filter layer cannot stop the live cluster's own traffic yet; treat
+/// `isolate_node`/`drop_message_type`/`drop_between` as building blocks for
+/// manually-driven message replay, not as real network-partition injection.
+pub type FilterRegistry = Arc<Mutex<HashMap<u16, NodeFilters>>>;
+
 #[allow(dead_code)]
 pub struct ServerCluster {
     // node_id -> server.
@@ -50,7 +110,9 @@ pub struct ServerCluster {
     env: Arc<Environment>,
     pd_client: Arc<TestPdClient>,
     security_mgr: Arc<SecurityManager>,
-    dfs: Arc<InMemFS>,
+    dfs: Arc<dyn Dfs>,
+    dfs_kind: DfsKind,
+    filters: FilterRegistry,
     channels: HashMap<u64, Channel>,
 }
 
@@ -58,17 +120,40 @@ impl ServerCluster {
     // The node id is statically assigned, the temp dir and server address are calculated by
     // the node id.
     pub fn new<F>(nodes: Vec<u16>, update_conf: F) -> ServerCluster
+    where
+        F: Fn(u16, &mut TiKvConfig),
+    {
+        Self::new_with_dfs(nodes, DfsKind::InMem, update_conf)
+    }
+
+    pub fn new_with_dfs<F>(nodes: Vec<u16>, dfs_kind: DfsKind, update_conf: F) -> ServerCluster
     where
         F: Fn(u16, &mut TiKvConfig),
     {
         tikv_util::thread_group::set_properties(Some(GroupProperties::default()));
+        let dfs: Arc<dyn Dfs> = match &dfs_kind {
+            DfsKind::InMem => Arc::new(InMemFS::new()),
+            DfsKind::S3 {
+                endpoint,
+                bucket,
+                key_id,
+                secret_key,
+            } => Arc::new(S3Fs::new(
+                bucket.clone(),
+                endpoint.clone(),
+                key_id.clone(),
+                secret_key.clone(),
+            )),
+        };
         let mut cluster = Self {
             servers: HashMap::new(),
             tmp_dir: TempDir::new().unwrap(),
             env: Arc::new(EnvBuilder::new().cq_count(2).build()),
             pd_client: Arc::new(TestPdClient::new(1, false)),
             security_mgr: Arc::new(SecurityManager::new(&Default::default()).unwrap()),
-            dfs: Arc::new(InMemFS::new()),
+            dfs,
+            dfs_kind,
+            filters: Arc::new(Mutex::new(HashMap::new())),
             channels: HashMap::new(),
         };
         for node_id in nodes {
@@ -82,6 +167,7 @@ impl ServerCluster {
         F: Fn(u16, &mut TiKvConfig),
     {
         let mut config = new_test_config(self.tmp_dir.path(), node_id);
+        apply_dfs_config(&mut config, &self.dfs_kind);
         update_conf(node_id, &mut config);
         let mut server = TiKVServer::setup(
             config,
@@ -146,19 +232,63 @@ impl ServerCluster {
     }
 
     pub fn kv_prewrite(&self, muts: Vec<Mutation>, pk: Vec<u8>, ts: TimeStamp) {
-        let ctx = self.new_rpc_context(&pk);
-        let kv_client = self.get_kv_client(ctx.get_peer().get_store_id());
+        let prewrite_req = self.new_prewrite_request(&muts, &pk, ts);
+        self.must_kv_prewrite(&prewrite_req);
+    }
+
+    /// Prewrites `muts` using the async-commit protocol: `pk` is the primary
+    /// key and `secondary_keys` lists the remaining keys so the server can
+    /// fill in `secondaries` on the primary lock. Returns the commit ts the
+    /// client should use, derived from the `min_commit_ts` the server picked
+    /// (falling back to `start_ts + 1` if the server didn't report one).
+    pub fn kv_prewrite_async_commit(
+        &self,
+        muts: Vec<Mutation>,
+        pk: Vec<u8>,
+        secondary_keys: Vec<Vec<u8>>,
+        ts: TimeStamp,
+    ) -> TimeStamp {
+        let mut prewrite_req = self.new_prewrite_request(&muts, &pk, ts);
+        prewrite_req.use_async_commit = true;
+        prewrite_req.set_secondaries(secondary_keys.into());
+        let prewrite_resp = self.must_kv_prewrite(&prewrite_req);
+        Self::resolve_async_commit_ts(ts, &prewrite_resp)
+    }
 
+    /// Prewrites `muts` with `try_one_pc` set so the prewrite itself commits
+    /// the transaction; no follow-up `kv_commit` is needed. Returns the
+    /// `one_pc_commit_ts` the server reported, or `start_ts + 1` if it
+    /// reported none; no `kv_commit` is sent in either case.
+    pub fn kv_prewrite_1pc(&self, muts: Vec<Mutation>, pk: Vec<u8>, ts: TimeStamp) -> TimeStamp {
+        let mut prewrite_req = self.new_prewrite_request(&muts, &pk, ts);
+        prewrite_req.try_one_pc = true;
+        let prewrite_resp = self.must_kv_prewrite(&prewrite_req);
+        Self::resolve_one_pc_commit_ts(ts, &prewrite_resp)
+    }
+
+    fn new_prewrite_request(
+        &self,
+        muts: &[Mutation],
+        pk: &[u8],
+        ts: TimeStamp,
+    ) -> PrewriteRequest {
+        let ctx = self.new_rpc_context(pk);
         let mut prewrite_req = PrewriteRequest::default();
         prewrite_req.set_context(ctx);
-        prewrite_req.set_mutations(muts.into());
-        prewrite_req.primary_lock = pk;
+        prewrite_req.set_mutations(muts.to_vec().into());
+        prewrite_req.primary_lock = pk.to_vec();
         prewrite_req.start_version = ts.into_inner();
         prewrite_req.lock_ttl = 3000;
         prewrite_req.min_commit_ts = prewrite_req.start_version + 1;
-        let mut prewrite_resp = kv_client.kv_prewrite(&prewrite_req).unwrap();
+        prewrite_req
+    }
+
+    fn must_kv_prewrite(&self, prewrite_req: &PrewriteRequest) -> PrewriteResponse {
+        let kv_client =
+            self.get_kv_client(prewrite_req.get_context().get_peer().get_store_id());
+        let mut prewrite_resp = kv_client.kv_prewrite(prewrite_req).unwrap();
         retry_req!(
-            kv_client.kv_prewrite(&prewrite_req).unwrap(),
+            kv_client.kv_prewrite(prewrite_req).unwrap(),
             !prewrite_resp.has_region_error() && prewrite_resp.errors.is_empty(),
             prewrite_resp,
             10,   // retry 10 times
@@ -174,6 +304,124 @@ impl ServerCluster {
             "{:?}",
             prewrite_resp.get_errors()
         );
+        prewrite_resp
+    }
+
+    /// The server reports the `min_commit_ts` it picked for an async-commit
+    /// prewrite; the actual commit ts is the max of that value across all
+    /// keys involved, or `start_ts + 1` if the server didn't advance it.
+    fn resolve_async_commit_ts(start_ts: TimeStamp, resp: &PrewriteResponse) -> TimeStamp {
+        let min_commit_ts = resp.get_min_commit_ts();
+        if min_commit_ts > start_ts.into_inner() {
+            TimeStamp::new(min_commit_ts)
+        } else {
+            TimeStamp::new(start_ts.into_inner() + 1)
+        }
+    }
+
+    /// A 1PC prewrite reports its commit ts via `one_pc_commit_ts`, not
+    /// `min_commit_ts` (which stays 0 on a 1PC response), or `start_ts + 1`
+    /// if the server didn't report one.
+    fn resolve_one_pc_commit_ts(start_ts: TimeStamp, resp: &PrewriteResponse) -> TimeStamp {
+        let one_pc_commit_ts = resp.get_one_pc_commit_ts();
+        if one_pc_commit_ts > start_ts.into_inner() {
+            TimeStamp::new(one_pc_commit_ts)
+        } else {
+            TimeStamp::new(start_ts.into_inner() + 1)
+        }
+    }
+
+    /// Acquires pessimistic locks on `keys` for the transaction starting at
+    /// `start_ts`, as of `for_update_ts`.
+    pub fn kv_pessimistic_lock(
+        &self,
+        keys: Vec<Vec<u8>>,
+        pk: Vec<u8>,
+        start_ts: TimeStamp,
+        for_update_ts: TimeStamp,
+    ) {
+        let ctx = self.new_rpc_context(&pk);
+        let kv_client = self.get_kv_client(ctx.get_peer().get_store_id());
+        let mutations = keys
+            .into_iter()
+            .map(|key| {
+                let mut m = Mutation::default();
+                m.set_op(Op::PessimisticLock);
+                m.set_key(key);
+                m
+            })
+            .collect();
+        let mut req = PessimisticLockRequest::default();
+        req.set_context(ctx);
+        req.set_mutations(mutations);
+        req.primary_lock = pk;
+        req.start_version = start_ts.into_inner();
+        req.for_update_ts = for_update_ts.into_inner();
+        req.lock_ttl = 3000;
+        let mut resp = kv_client.kv_pessimistic_lock(&req).unwrap();
+        retry_req!(
+            kv_client.kv_pessimistic_lock(&req).unwrap(),
+            !resp.has_region_error() && resp.errors.is_empty(),
+            resp,
+            10,   // retry 10 times
+            3000  // 3s timeout
+        );
+        assert!(!resp.has_region_error(), "{:?}", resp.get_region_error());
+        assert!(resp.errors.is_empty(), "{:?}", resp.get_errors());
+    }
+
+    pub fn kv_pessimistic_rollback(
+        &self,
+        keys: Vec<Vec<u8>>,
+        start_ts: TimeStamp,
+        for_update_ts: TimeStamp,
+    ) {
+        let ctx = self.new_rpc_context(keys.first().unwrap());
+        let kv_client = self.get_kv_client(ctx.get_peer().get_store_id());
+        let mut req = PessimisticRollbackRequest::default();
+        req.set_context(ctx);
+        req.set_keys(keys.into());
+        req.start_version = start_ts.into_inner();
+        req.for_update_ts = for_update_ts.into_inner();
+        let resp = kv_client.kv_pessimistic_rollback(&req).unwrap();
+        assert!(!resp.has_region_error(), "{:?}", resp.get_region_error());
+        assert!(resp.errors.is_empty(), "{:?}", resp.get_errors());
+    }
+
+    /// Prewrites a pessimistic transaction. `is_pessimistic_lock` mirrors
+    /// `muts` and marks which mutations are backed by a pessimistic lock
+    /// acquired via [`Self::kv_pessimistic_lock`].
+    pub fn kv_prewrite_pessimistic(
+        &self,
+        muts: Vec<Mutation>,
+        pk: Vec<u8>,
+        start_ts: TimeStamp,
+        for_update_ts: TimeStamp,
+        is_pessimistic_lock: Vec<bool>,
+    ) {
+        let mut prewrite_req = self.new_prewrite_request(&muts, &pk, start_ts);
+        prewrite_req.for_update_ts = for_update_ts.into_inner();
+        prewrite_req.set_is_pessimistic_lock(is_pessimistic_lock);
+        self.must_kv_prewrite(&prewrite_req);
+    }
+
+    /// Prewrites and commits an arbitrary set of mutations (Put/Del/Insert/
+    /// CheckNotExists) in one transaction, so tests aren't limited to the
+    /// monotonic Put ranges that [`Self::put_kv`] generates.
+    pub fn batch_mutate(&self, mutations: Vec<Mutation>, pk: Vec<u8>, ts: TimeStamp) {
+        // CheckNotExists leaves no lock behind, so there is nothing to commit
+        // for those keys.
+        let keys: Vec<Vec<u8>> = mutations
+            .iter()
+            .filter(|m| m.get_op() != Op::CheckNotExists)
+            .map(|m| m.get_key().to_vec())
+            .collect();
+        self.kv_prewrite(mutations, pk, ts);
+        if keys.is_empty() {
+            return;
+        }
+        let commit_ts = self.get_ts();
+        self.kv_commit(keys, ts, commit_ts);
     }
 
     pub fn kv_commit(&self, keys: Vec<Vec<u8>>, start_ts: TimeStamp, commit_ts: TimeStamp) {
@@ -244,6 +492,54 @@ impl ServerCluster {
         server.get_sst_importer()
     }
 
+    pub fn get_concurrency_manager(&self, node_id: u16) -> ConcurrencyManager {
+        let server = self.servers.get(&node_id).unwrap();
+        server.get_concurrency_manager()
+    }
+
+    /// Polls the node's concurrency manager until `max_ts()` reaches at least
+    /// `ts`. Used to assert that a read-index request (or any other max_ts
+    /// advancing action) has taken effect before a racing prewrite runs.
+    pub fn must_max_ts_at_least(&self, node_id: u16, ts: TimeStamp) {
+        let cm = self.get_concurrency_manager(node_id);
+        for _ in 0..30 {
+            if cm.max_ts() >= ts {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        panic!(
+            "max_ts of node {} did not reach {}, got {}",
+            node_id,
+            ts,
+            cm.max_ts()
+        );
+    }
+
+    /// Inserts a lock directly into the node's in-memory lock table, without
+    /// going through prewrite, so tests can reproduce the prewrite-vs-
+    /// read-index race. The lock table entry stays alive only as long as the
+    /// returned guard is held, so the caller must keep (and eventually drop)
+    /// it to release the lock.
+    #[must_use]
+    pub fn lock_key_in_memory(&self, node_id: u16, key: &[u8], ts: TimeStamp) -> KeyHandleGuard {
+        let cm = self.get_concurrency_manager(node_id);
+        let key = Key::from_raw(key);
+        let lock = Lock::new(
+            LockType::Put,
+            key.as_encoded().clone(),
+            ts,
+            3000,
+            None,
+            TimeStamp::zero(),
+            1,
+            ts,
+        );
+        let guard = block_on(cm.lock_key(&key));
+        guard.with_lock(|l| *l = Some(lock));
+        guard
+    }
+
     pub fn split(&self, key: &[u8]) {
         for _ in 0..10 {
             let ctx = self.new_rpc_context(key);
@@ -266,8 +562,186 @@ impl ServerCluster {
     }
 
     pub fn send_raft_command(&self, node_id: u16, cmd: RaftCmdRequest) {
+        self.get_raft_router(node_id).send_command(cmd, Callback::None);
+    }
+
+    pub fn get_raft_router(&self, node_id: u16) -> RaftRouter {
         let server = self.servers.get(&node_id).unwrap();
-        server.get_raft_router().send_command(cmd, Callback::None);
+        server.get_raft_router()
+    }
+
+    /// Replays a manually-built `msg` as if it came from `from_node_id`:
+    /// `from_node_id`'s send filters run first, then the destination node's
+    /// recv filters, and only if both let it through does the message reach
+    /// the destination via `RaftStoreRouter::send_raft_message`. This does
+    /// NOT intercept the cluster's own organic Raft traffic (see the
+    /// [`FilterRegistry`] note) -- it only filters messages a test builds
+    /// and passes in here itself.
+    pub fn send_raft_message(&self, from_node_id: u16, msg: RaftMessage) {
+        match self.filter_action(from_node_id, &msg, true) {
+            FilterAction::Drop => return,
+            FilterAction::Delay(d) => std::thread::sleep(d),
+            FilterAction::Pass => {}
+        }
+        let to_node_id = self.get_server_node_id(msg.get_to_peer().get_store_id());
+        match self.filter_action(to_node_id, &msg, false) {
+            FilterAction::Drop => return,
+            FilterAction::Delay(d) => std::thread::sleep(d),
+            FilterAction::Pass => {}
+        }
+        self.get_raft_router(to_node_id)
+            .send_raft_message(msg)
+            .unwrap();
+    }
+
+    fn filter_action(&self, node_id: u16, msg: &RaftMessage, outgoing: bool) -> FilterAction {
+        let filters = self.filters.lock().unwrap();
+        let chain = match filters.get(&node_id) {
+            Some(f) if outgoing => &f.send,
+            Some(f) => &f.recv,
+            None => return FilterAction::Pass,
+        };
+        for f in chain {
+            match f(msg) {
+                FilterAction::Pass => continue,
+                other => return other,
+            }
+        }
+        FilterAction::Pass
+    }
+
+    /// Installs a filter on Raft messages `node_id` sends out, enforced by
+    /// [`Self::send_raft_message`].
+    pub fn add_send_filter<F>(&self, node_id: u16, filter: F)
+    where
+        F: Fn(&RaftMessage) -> FilterAction + Send + Sync + 'static,
+    {
+        self.filters
+            .lock()
+            .unwrap()
+            .entry(node_id)
+            .or_default()
+            .send
+            .push(Arc::new(filter));
+    }
+
+    /// Installs a filter on Raft messages `node_id` receives, enforced by
+    /// [`Self::send_raft_message`].
+    pub fn add_recv_filter<F>(&self, node_id: u16, filter: F)
+    where
+        F: Fn(&RaftMessage) -> FilterAction + Send + Sync + 'static,
+    {
+        self.filters
+            .lock()
+            .unwrap()
+            .entry(node_id)
+            .or_default()
+            .recv
+            .push(Arc::new(filter));
+    }
+
+    pub fn clear_filters(&self, node_id: u16) {
+        self.filters.lock().unwrap().remove(&node_id);
+    }
+
+    /// Drops every Raft message sent or received by `node_id` through
+    /// [`Self::send_raft_message`]. Does not stop the live cluster's own
+    /// heartbeat/replication traffic -- see the [`FilterRegistry`] note.
+    pub fn isolate_node(&self, node_id: u16) {
+        self.add_send_filter(node_id, |_: &RaftMessage| FilterAction::Drop);
+        self.add_recv_filter(node_id, |_: &RaftMessage| FilterAction::Drop);
+    }
+
+    /// Drops outgoing messages of `msg_type` sent by `node_id` through
+    /// [`Self::send_raft_message`].
+    pub fn drop_message_type(&self, node_id: u16, msg_type: MessageType) {
+        self.add_send_filter(node_id, move |msg: &RaftMessage| {
+            if msg.get_message().get_msg_type() == msg_type {
+                FilterAction::Drop
+            } else {
+                FilterAction::Pass
+            }
+        });
+    }
+
+    /// Drops messages sent from `from_node_id` to `to_node_id` through
+    /// [`Self::send_raft_message`]. Does not stop the live cluster's own
+    /// heartbeat/replication traffic -- see the [`FilterRegistry`] note.
+    pub fn drop_between(&self, from_node_id: u16, to_node_id: u16) {
+        let to_store_id = self.servers.get(&to_node_id).unwrap().get_store_id();
+        self.add_send_filter(from_node_id, move |msg: &RaftMessage| {
+            if msg.get_to_peer().get_store_id() == to_store_id {
+                FilterAction::Drop
+            } else {
+                FilterAction::Pass
+            }
+        });
+    }
+
+    /// Reads `key` at `ts` through the normal leader read path.
+    pub fn kv_get(&self, key: &[u8], ts: TimeStamp) -> Vec<u8> {
+        let ctx = self.new_rpc_context(key);
+        self.must_kv_get(ctx, key, ts)
+    }
+
+    pub fn kv_batch_get(&self, keys: Vec<Vec<u8>>, ts: TimeStamp) -> Vec<KvPair> {
+        let ctx = self.new_rpc_context(keys.first().unwrap());
+        let kv_client = self.get_kv_client(ctx.get_peer().get_store_id());
+        let mut req = BatchGetRequest::default();
+        req.set_context(ctx);
+        req.set_keys(keys.into());
+        req.set_version(ts.into_inner());
+        let mut resp = kv_client.kv_batch_get(&req).unwrap();
+        retry_req!(
+            kv_client.kv_batch_get(&req).unwrap(),
+            !resp.has_region_error(),
+            resp,
+            10,   // retry 10 times
+            3000  // 3s timeout
+        );
+        assert!(!resp.has_region_error(), "{:?}", resp.get_region_error());
+        resp.take_pairs().into_vec()
+    }
+
+    /// Reads `key` at `ts` from `follower_node_id`, which must not be the
+    /// region's leader. `Context.replica_read` is set so the server has to
+    /// run a ReadIndex round against the leader before serving the read.
+    pub fn replica_read(&self, follower_node_id: u16, key: &[u8], ts: TimeStamp) -> Vec<u8> {
+        let region_info = self.pd_client.get_region_info(key).unwrap();
+        let store_id = self.servers.get(&follower_node_id).unwrap().get_store_id();
+        let peer = find_peer(&region_info.region, store_id)
+            .unwrap_or_else(|| panic!("node {} has no peer in the region", follower_node_id))
+            .clone();
+        assert_ne!(
+            peer, region_info.leader.clone().unwrap(),
+            "node {} is the leader, replica_read requires a follower",
+            follower_node_id
+        );
+        let mut ctx = Context::new();
+        ctx.set_region_id(region_info.get_id());
+        ctx.set_region_epoch(region_info.get_region_epoch().clone());
+        ctx.set_peer(peer);
+        ctx.set_replica_read(true);
+        self.must_kv_get(ctx, key, ts)
+    }
+
+    fn must_kv_get(&self, ctx: Context, key: &[u8], ts: TimeStamp) -> Vec<u8> {
+        let kv_client = self.get_kv_client(ctx.get_peer().get_store_id());
+        let mut req = GetRequest::default();
+        req.set_context(ctx);
+        req.set_key(key.to_vec());
+        req.set_version(ts.into_inner());
+        let mut resp = kv_client.kv_get(&req).unwrap();
+        retry_req!(
+            kv_client.kv_get(&req).unwrap(),
+            !resp.has_region_error(),
+            resp,
+            10,   // retry 10 times
+            3000  // 3s timeout
+        );
+        assert!(!resp.has_region_error(), "{:?}", resp.get_region_error());
+        assert!(!resp.has_error(), "{:?}", resp.get_error());
+        resp.take_value()
     }
 
     pub fn wait_region_replicated(&self, key: &[u8], replica_cnt: usize) {
@@ -340,7 +814,6 @@ pub fn new_test_config(base_dir: &Path, node_id: u16) -> TiKvConfig {
     config.server.cluster_id = 1;
     config.server.addr = node_addr(node_id);
     config.server.status_addr = node_status_addr(node_id);
-    config.dfs.s3_endpoint = "memory".to_string();
     config.raft_store.raft_base_tick_interval = ReadableDuration::millis(10);
     config.raft_store.raft_store_max_leader_lease = ReadableDuration::millis(20);
     config.raft_store.split_region_check_tick_interval = ReadableDuration::millis(100);
@@ -353,6 +826,23 @@ pub fn new_test_config(base_dir: &Path, node_id: u16) -> TiKvConfig {
     config
 }
 
+fn apply_dfs_config(config: &mut TiKvConfig, dfs_kind: &DfsKind) {
+    match dfs_kind {
+        DfsKind::InMem => config.dfs.s3_endpoint = "memory".to_string(),
+        DfsKind::S3 {
+            endpoint,
+            bucket,
+            key_id,
+            secret_key,
+        } => {
+            config.dfs.s3_endpoint = endpoint.clone();
+            config.dfs.s3_bucket = bucket.clone();
+            config.dfs.s3_key_id = key_id.clone();
+            config.dfs.s3_secret_key = secret_key.clone();
+        }
+    }
+}
+
 fn node_addr(node_id: u16) -> String {
     format!("127.0.0.1:2{:04}", node_id)
 }